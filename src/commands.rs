@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use futures::future::join_all;
+use futures::Future;
+use regex::Regex;
+
+use bot::telegram::Bot;
+use database::Database;
+use error::Error;
+use kitsu::Api;
+
+/// Shared state handed to every command on dispatch, so registering a new
+/// command never requires touching `Handler` itself.
+pub struct Context {
+  pub bot: Bot,
+  pub api: Api,
+  pub db: Database,
+}
+
+pub trait MsgCommandHandler {
+  /// The literal prefix this command answers to, e.g. `"/list"`.
+  fn prefix(&self) -> &str;
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>>;
+}
+
+pub trait QueryCommandHandler {
+  /// The verb segment of the callback data, e.g. `"offset"` in
+  /// `/{kitsu_id}/offset/{offset}/`.
+  fn prefix(&self) -> &str;
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    msg_id: i64,
+    chat_id: i64,
+    user_id: i64,
+    query_id: String,
+    args: &str,
+  ) -> Box<Future<Item = (), Error = Error>>;
+}
+
+pub trait RegexCommand {
+  fn regex(&self) -> &Regex;
+
+  /// `matches` holds the owned capture groups (index 0 is the whole match),
+  /// `None` entries mark groups the regex didn't capture this time.
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    matches: Vec<Option<String>>,
+  ) -> Box<Future<Item = (), Error = Error>>;
+}
+
+#[derive(Default)]
+pub struct MsgCommandRegistry {
+  commands: HashMap<String, Box<MsgCommandHandler>>,
+  regexes: Vec<Box<RegexCommand>>,
+}
+
+impl MsgCommandRegistry {
+  pub fn new() -> MsgCommandRegistry {
+    MsgCommandRegistry {
+      commands: HashMap::new(),
+      regexes: Vec::new(),
+    }
+  }
+
+  pub fn register(&mut self, command: Box<MsgCommandHandler>) {
+    self.commands.insert(command.prefix().to_owned(), command);
+  }
+
+  pub fn register_regex(&mut self, command: Box<RegexCommand>) {
+    self.regexes.push(command);
+  }
+
+  /// Splits `text` on the first space into `(prefix, rest)`, looks up an
+  /// exact match, and falls through to the regex list before giving up.
+  pub fn dispatch(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    text: &str,
+  ) -> Option<Box<Future<Item = (), Error = Error>>> {
+    let (prefix, rest) = match text.find(' ') {
+      Some(idx) => (&text[..idx], Some(text[idx + 1..].trim())),
+      None => (text, None),
+    };
+
+    if let Some(command) = self.commands.get_mut(prefix) {
+      return Some(command.execute(ctx, chat_id, user_id, rest));
+    }
+
+    for command in &mut self.regexes {
+      // `captures_iter` so a message dropping several links (e.g. two Kitsu
+      // URLs) gets a reply for each one, not just the first.
+      let all_matches: Vec<Vec<Option<String>>> = command
+        .regex()
+        .captures_iter(text)
+        .map(|captures| {
+          captures
+            .iter()
+            .map(|m| m.map(|m| m.as_str().to_owned()))
+            .collect()
+        })
+        .collect();
+
+      if all_matches.is_empty() {
+        continue;
+      }
+
+      // One link erroring out (e.g. a 404) shouldn't suppress the replies
+      // for the other links in the same message, so swallow per-link errors
+      // before joining them.
+      let futures = all_matches
+        .into_iter()
+        .map(|matches| command.execute(ctx, chat_id, user_id, matches).then(|_| Ok(())))
+        .collect::<Vec<_>>();
+      return Some(Box::new(join_all(futures).map(|_| ())));
+    }
+
+    None
+  }
+}
+
+#[derive(Default)]
+pub struct QueryCommandRegistry {
+  commands: HashMap<String, Box<QueryCommandHandler>>,
+}
+
+impl QueryCommandRegistry {
+  pub fn new() -> QueryCommandRegistry {
+    QueryCommandRegistry {
+      commands: HashMap::new(),
+    }
+  }
+
+  pub fn register(&mut self, command: Box<QueryCommandHandler>) {
+    self.commands.insert(command.prefix().to_owned(), command);
+  }
+
+  /// Callback data looks like `/{kitsu_id}/{verb}/{rest...}/`; the verb is
+  /// the second `/`-separated segment.
+  pub fn dispatch(
+    &mut self,
+    ctx: &mut Context,
+    msg_id: i64,
+    chat_id: i64,
+    user_id: i64,
+    query_id: String,
+    data: &str,
+  ) -> Option<Box<Future<Item = (), Error = Error>>> {
+    let mut parts = data.trim_matches('/').splitn(2, '/');
+    let kitsu_id = parts.next()?;
+    let rest = parts.next()?;
+    let mut rest_parts = rest.splitn(2, '/');
+    let verb = rest_parts.next()?;
+    let args = rest_parts.next().unwrap_or("");
+
+    self
+      .commands
+      .get_mut(verb)
+      .map(|command| command.execute(ctx, msg_id, chat_id, user_id, query_id, &format!("{}/{}", kitsu_id, args)))
+  }
+}
@@ -0,0 +1,115 @@
+use futures::Future;
+
+use commands::Context;
+use error::Error;
+
+/// Per-user finite-state dialogue, persisted in `Database` so it survives
+/// restarts. Currently only drives the in-chat Kitsu registration flow.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DialogueState {
+  AwaitingEmail,
+  AwaitingPassword { email: String },
+}
+
+pub fn start(ctx: &mut Context, user_id: i64) {
+  ctx.db.set_dialogue_state(user_id, DialogueState::AwaitingEmail);
+}
+
+pub fn cancel(
+  ctx: &mut Context,
+  chat_id: i64,
+  user_id: i64,
+) -> Box<Future<Item = (), Error = Error>> {
+  ctx.db.clear_dialogue_state(user_id);
+  Box::new(
+    ctx
+      .bot
+      .send_message(chat_id, String::from("Registration cancelled."), None, None, None)
+      .and_then(|msg| {
+        info!("send message: {:?} in {:?}", msg.text, msg.chat);
+        Ok(())
+      }),
+  )
+}
+
+/// Consumes the next plain message as part of whichever state the user is
+/// in, advancing or finishing the dialogue.
+pub fn advance(
+  ctx: &mut Context,
+  chat_id: i64,
+  user_id: i64,
+  msg_id: i64,
+  state: DialogueState,
+  text: &str,
+) -> Box<Future<Item = (), Error = Error>> {
+  match state {
+    DialogueState::AwaitingEmail => {
+      ctx.db.set_dialogue_state(
+        user_id,
+        DialogueState::AwaitingPassword {
+          email: text.to_owned(),
+        },
+      );
+      Box::new(
+        ctx
+          .bot
+          .send_message(
+            chat_id,
+            String::from("Thanks. Now send your Kitsu password."),
+            None,
+            None,
+            None,
+          )
+          .and_then(|msg| {
+            info!("send message: {:?} in {:?}", msg.text, msg.chat);
+            Ok(())
+          }),
+      )
+    }
+    DialogueState::AwaitingPassword { email } => {
+      let bot = ctx.bot.clone();
+      let bot2 = ctx.bot.clone();
+      let db = ctx.db.clone();
+      let password = text.to_owned();
+
+      Box::new(
+        ctx
+          .api
+          .fetch_token(email, password)
+          .then(move |result| {
+            // Consumed; delete the password from the chat regardless of outcome.
+            bot2
+              .delete_message(chat_id, msg_id)
+              .then(move |_| Ok(result))
+          })
+          .and_then(move |result| match result {
+            Ok((token, kitsu_id)) => {
+              db.clear_dialogue_state(user_id);
+              db.save_registration(user_id, kitsu_id, token);
+              bot.send_message(
+                chat_id,
+                String::from("Registered! Try /list to see your library."),
+                None,
+                None,
+                None,
+              )
+            }
+            Err(_) => {
+              db.set_dialogue_state(user_id, DialogueState::AwaitingEmail);
+              bot.send_message(
+                chat_id,
+                String::from("Invalid credentials, please send your email again, or /cancel."),
+                None,
+                None,
+                None,
+              )
+            }
+          })
+          .and_then(|msg| {
+            info!("send message: {:?} in {:?}", msg.text, msg.chat);
+            Ok(())
+          }),
+      )
+    }
+  }
+}
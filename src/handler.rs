@@ -1,10 +1,16 @@
-use nom::IResult;
+use std::time::Duration;
+
 use futures::{done, Future};
 
+use regex::Regex;
+
 use bot::telegram::Bot;
+use commands::{Context, MsgCommandHandler, MsgCommandRegistry, QueryCommandHandler, QueryCommandRegistry, RegexCommand};
+use dialogue::{self, DialogueState};
 use kitsu::Api;
 use error::{Error, TelegramError};
-use types::{Client, MsgCommand, QueryCommand};
+use scheduler;
+use types::Client;
 use utils::*;
 use types::telegram::{CallbackQuery, InlineKeyboardButton, Message, ParseMode};
 use database::Database;
@@ -12,34 +18,85 @@ use database::Database;
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
 pub struct Handler {
-  api: Api,
-  bot: Bot,
-  db: Database,
+  ctx: Context,
+  msg_commands: MsgCommandRegistry,
+  query_commands: QueryCommandRegistry,
 }
 
 impl Handler {
   pub fn new(bot: Bot, client: Client, token: String) -> Handler {
-    Handler {
+    let ctx = Context {
       bot,
       api: Api::new(client.clone()),
       db: Database::new(token, client),
+    };
+
+    let mut msg_commands = MsgCommandRegistry::new();
+    msg_commands.register(Box::new(ListCommand));
+    msg_commands.register(Box::new(UpdateCommand));
+    msg_commands.register(Box::new(VersionCommand));
+    msg_commands.register(Box::new(RegisterCommand));
+    msg_commands.register(Box::new(NotifyCommand));
+    msg_commands.register(Box::new(SearchCommand));
+    msg_commands.register_regex(Box::new(LinkCommand::new()));
+
+    let mut query_commands = QueryCommandRegistry::new();
+    query_commands.register(Box::new(OffsetCommand));
+    query_commands.register(Box::new(DetailCommand));
+    query_commands.register(Box::new(ProgressCommand));
+    query_commands.register(Box::new(SearchOffsetCommand));
+    query_commands.register(Box::new(AddCommand));
+
+    Handler {
+      ctx,
+      msg_commands,
+      query_commands,
     }
   }
 
+  /// Builds the recurring new-episode sweep over a clone of this handler's
+  /// context; hand the result to `tokio::spawn` alongside the bot's poll
+  /// loop.
+  pub fn spawn_scheduler(&self, interval: Duration) -> Box<Future<Item = (), Error = ()> + Send> {
+    scheduler::spawn(
+      self.ctx.bot.clone(),
+      self.ctx.api.clone(),
+      self.ctx.db.clone(),
+      interval,
+    )
+  }
+
   pub fn handle_message(&mut self, msg: Message) -> Box<Future<Item = (), Error = Error>> {
     let chat_id = msg.chat.unwrap().id;
     let user_id = msg.from.unwrap().id;
+    let msg_id = msg.message_id.unwrap();
     let text = msg.text.unwrap_or(String::new());
+    let state = self.ctx.db.get_dialogue_state(user_id);
 
-    info!("received message: '{}' from {}, in {}", text, user_id, text);
+    // Never log the raw text while a password is in flight; it's deleted
+    // from the chat for the same reason once the dialogue consumes it.
+    match &state {
+      Some(DialogueState::AwaitingPassword { .. }) => {
+        info!("received message: '<redacted>' from {}, in {}", user_id, chat_id)
+      }
+      _ => info!("received message: '{}' from {}, in {}", text, user_id, chat_id),
+    }
+
+    // A pending registration dialogue takes priority over normal parsing.
+    if let Some(state) = state {
+      return if text.trim() == "/cancel" {
+        dialogue::cancel(&mut self.ctx, chat_id, user_id)
+      } else {
+        dialogue::advance(&mut self.ctx, chat_id, user_id, msg_id, state, &text)
+      };
+    }
 
-    match parse_message(&text) {
-      IResult::Done(_, command) => match command {
-        MsgCommand::List => self.list(user_id, chat_id),
-        MsgCommand::Update => self.update(chat_id),
-        MsgCommand::Version => self.version(chat_id),
-      },
-      _ => self.unknown(chat_id),
+    match self
+      .msg_commands
+      .dispatch(&mut self.ctx, chat_id, user_id, &text)
+    {
+      Some(future) => future,
+      None => unknown(&self.ctx.bot, chat_id),
     }
   }
 
@@ -55,31 +112,12 @@ impl Handler {
         let msg_id = msg.message_id.unwrap();
         let chat_id = msg.chat.unwrap().id;
 
-        match parse_query(&data) {
-          IResult::Done(_, command) => match command {
-            QueryCommand::Offset { kitsu_id, offset } => {
-              self.offset(msg_id, chat_id, kitsu_id, offset, query_id)
-            }
-            QueryCommand::Detail { kitsu_id, anime_id } => {
-              self.detail(msg_id, chat_id, kitsu_id, anime_id, query_id)
-            }
-            QueryCommand::Progress {
-              kitsu_id,
-              anime_id,
-              entry_id,
-              progress,
-            } => self.progress(
-              msg_id,
-              chat_id,
-              user_id,
-              kitsu_id,
-              anime_id,
-              progress,
-              entry_id,
-              query_id,
-            ),
-          },
-          _ => self.unknown(chat_id),
+        match self
+          .query_commands
+          .dispatch(&mut self.ctx, msg_id, chat_id, user_id, query_id, &data)
+        {
+          Some(future) => future,
+          None => unknown(&self.ctx.bot, chat_id),
         }
       }
       None => Box::new(done::<_, Error>(
@@ -87,42 +125,35 @@ impl Handler {
       )),
     }
   }
+}
 
-  fn unknown(&self, chat_id: i64) -> Box<Future<Item = (), Error = Error>> {
-    Box::new(
-      self
-        .bot
-        .send_message(chat_id, String::from("Unknown command."), None, None)
-        .and_then(|msg| {
-          info!("send message: {:?} in {:?}", msg.text, msg.chat);
-          Ok(())
-        }),
-    )
-  }
+fn unknown(bot: &Bot, chat_id: i64) -> Box<Future<Item = (), Error = Error>> {
+  Box::new(
+    bot
+      .send_message(chat_id, String::from("Unknown command."), None, None, None)
+      .and_then(|msg| {
+        info!("send message: {:?} in {:?}", msg.text, msg.chat);
+        Ok(())
+      }),
+  )
+}
 
-  fn version(&self, chat_id: i64) -> Box<Future<Item = (), Error = Error>> {
-    Box::new(
-      self
-        .bot
-        .send_message(
-          chat_id,
-          format!(
-            "<pre>Sagiri-{}\nFor more information, please visit the wiki.</pre>",
-            VERSION
-          ),
-          Some(ParseMode::HTML),
-          None,
-        )
-        .and_then(|msg| {
-          info!("send message: {:?} in {:?}", msg.text, msg.chat);
-          Ok(())
-        }),
-    )
+struct ListCommand;
+
+impl MsgCommandHandler for ListCommand {
+  fn prefix(&self) -> &str {
+    "/list"
   }
 
-  fn list(&mut self, user_id: i64, chat_id: i64) -> Box<Future<Item = (), Error = Error>> {
-    let bot = self.bot.clone();
-    match self.db.get_kitsu_id(user_id) {
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    _args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    let bot = ctx.bot.clone();
+    match ctx.db.get_kitsu_id(user_id) {
       None => Box::new(
         bot
           .send_message(
@@ -130,6 +161,7 @@ impl Handler {
             format!("Non-registered user: {}", user_id),
             None,
             None,
+            None,
           )
           .and_then(|msg| {
             info!("send message: {:?} in {:?}", msg.text, msg.chat);
@@ -137,14 +169,14 @@ impl Handler {
           }),
       ),
       Some(kitsu_id) => Box::new(
-        self
+        ctx
           .api
           .fetch_anime(kitsu_id, 0)
           .and_then(move |(prev, next, entries, animes)| {
             Ok(parse_anime_list(kitsu_id, prev, next, entries, animes))
           })
           .and_then(move |(text, buttons)| {
-            bot.send_message(chat_id, text, Some(ParseMode::HTML), Some(buttons))
+            bot.send_message(chat_id, text, Some(ParseMode::HTML), Some(buttons), None)
           })
           .and_then(|msg| {
             info!("send message: {:?} in {:?}", msg.text, msg.chat);
@@ -153,11 +185,25 @@ impl Handler {
       ),
     }
   }
+}
+
+struct UpdateCommand;
+
+impl MsgCommandHandler for UpdateCommand {
+  fn prefix(&self) -> &str {
+    "/update"
+  }
 
-  fn update(&mut self, chat_id: i64) -> Box<Future<Item = (), Error = Error>> {
-    let bot = self.bot.clone();
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    _user_id: i64,
+    _args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    let bot = ctx.bot.clone();
     Box::new(
-      self
+      ctx
         .db
         .fetch()
         .and_then(move |users| {
@@ -166,6 +212,7 @@ impl Handler {
             format!("<pre>Successful update: {} user(s)</pre>", users.len()),
             Some(ParseMode::HTML),
             None,
+            None,
           )
         })
         .and_then(|msg| {
@@ -174,48 +221,318 @@ impl Handler {
         }),
     )
   }
+}
+
+struct VersionCommand;
+
+impl MsgCommandHandler for VersionCommand {
+  fn prefix(&self) -> &str {
+    "/version"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    _user_id: i64,
+    _args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    Box::new(
+      ctx
+        .bot
+        .send_message(
+          chat_id,
+          format!(
+            "<pre>Sagiri-{}\nFor more information, please visit the wiki.</pre>",
+            VERSION
+          ),
+          Some(ParseMode::HTML),
+          None,
+          None,
+        )
+        .and_then(|msg| {
+          info!("send message: {:?} in {:?}", msg.text, msg.chat);
+          Ok(())
+        }),
+    )
+  }
+}
+
+struct RegisterCommand;
+
+impl MsgCommandHandler for RegisterCommand {
+  fn prefix(&self) -> &str {
+    "/register"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    _args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    dialogue::start(ctx, user_id);
+    Box::new(
+      ctx
+        .bot
+        .send_message(
+          chat_id,
+          String::from("Please send your Kitsu account email, or /cancel to abort."),
+          None,
+          None,
+          None,
+        )
+        .and_then(|msg| {
+          info!("send message: {:?} in {:?}", msg.text, msg.chat);
+          Ok(())
+        }),
+    )
+  }
+}
+
+struct NotifyCommand;
+
+impl MsgCommandHandler for NotifyCommand {
+  fn prefix(&self) -> &str {
+    "/notify"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    let enabled = match args.map(str::trim) {
+      Some("on") => true,
+      Some("off") => false,
+      _ => {
+        return Box::new(
+          ctx
+            .bot
+            .send_message(chat_id, String::from("Usage: /notify on|off"), None, None, None)
+            .and_then(|msg| {
+              info!("send message: {:?} in {:?}", msg.text, msg.chat);
+              Ok(())
+            }),
+        )
+      }
+    };
+
+    ctx.db.set_notify(user_id, enabled);
+    let text = if enabled {
+      "New-episode notifications are now on."
+    } else {
+      "New-episode notifications are now off."
+    };
+
+    Box::new(
+      ctx
+        .bot
+        .send_message(chat_id, String::from(text), None, None, None)
+        .and_then(|msg| {
+          info!("send message: {:?} in {:?}", msg.text, msg.chat);
+          Ok(())
+        }),
+    )
+  }
+}
+
+struct SearchCommand;
+
+impl MsgCommandHandler for SearchCommand {
+  fn prefix(&self) -> &str {
+    "/search"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    args: Option<&str>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    let query = match args.map(str::trim) {
+      Some(query) if !query.is_empty() => query.to_owned(),
+      _ => {
+        return Box::new(
+          ctx
+            .bot
+            .send_message(chat_id, String::from("Usage: /search <title>"), None, None, None)
+            .and_then(|msg| {
+              info!("send message: {:?} in {:?}", msg.text, msg.chat);
+              Ok(())
+            }),
+        )
+      }
+    };
+
+    let kitsu_id = ctx.db.get_kitsu_id(user_id).unwrap_or(0);
+    let bot = ctx.bot.clone();
+    Box::new(
+      ctx
+        .api
+        .search_anime(query.clone(), 0)
+        .and_then(move |(prev, next, animes)| {
+          Ok(parse_search_results(kitsu_id, query, prev, next, animes))
+        })
+        .and_then(move |(text, buttons)| {
+          bot.send_message(chat_id, text, Some(ParseMode::HTML), Some(buttons), None)
+        })
+        .and_then(|msg| {
+          info!("send message: {:?} in {:?}", msg.text, msg.chat);
+          Ok(())
+        }),
+    )
+  }
+}
+
+struct LinkCommand {
+  regex: Regex,
+}
+
+impl LinkCommand {
+  fn new() -> LinkCommand {
+    LinkCommand {
+      regex: Regex::new(r"https?://kitsu\.io/anime/([a-zA-Z0-9-]+)").unwrap(),
+    }
+  }
+}
 
-  fn offset(
-    &self,
+impl RegexCommand for LinkCommand {
+  fn regex(&self) -> &Regex {
+    &self.regex
+  }
+
+  /// `matches[1]` is whatever Kitsu put after `/anime/` in the URL: either a
+  /// numeric id or a slug like `attack-on-titan`. The sender's kitsu id
+  /// defaults to `0` when they aren't registered, same sentinel the query
+  /// commands already use for a missing/invalid id; `get_anime`/`get_anime_by_slug`
+  /// and `parse_anime_detail` fall back to a buttonless card in that case.
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    chat_id: i64,
+    user_id: i64,
+    matches: Vec<Option<String>>,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    let slug_or_id = matches.get(1).and_then(|m| m.clone()).unwrap_or_default();
+    let kitsu_id = ctx.db.get_kitsu_id(user_id).unwrap_or(0);
+
+    let pair: Box<Future<Item = _, Error = Error>> = match slug_or_id.parse::<i64>() {
+      Ok(anime_id) => Box::new(ctx.api.get_anime(kitsu_id, anime_id)),
+      Err(_) => Box::new(ctx.api.get_anime_by_slug(kitsu_id, slug_or_id)),
+    };
+
+    let bot = ctx.bot.clone();
+    Box::new(
+      pair
+        .and_then(move |pair| Ok(parse_anime_detail(kitsu_id, pair)))
+        .and_then(move |(text, buttons)| {
+          // Suppress the link preview: the card already renders the poster inline.
+          bot.send_message(chat_id, text, Some(ParseMode::HTML), Some(buttons), Some(true))
+        })
+        .and_then(|msg| {
+          info!("send message: {:?} in {:?}", msg.text, msg.chat);
+          Ok(())
+        }),
+    )
+  }
+}
+
+struct OffsetCommand;
+
+impl QueryCommandHandler for OffsetCommand {
+  fn prefix(&self) -> &str {
+    "offset"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
     msg_id: i64,
     chat_id: i64,
-    kitsu_id: i64,
-    offset: i64,
+    _user_id: i64,
     query_id: String,
+    args: &str,
   ) -> Box<Future<Item = (), Error = Error>> {
-    let bot1 = self.bot.clone();
-    let bot2 = self.bot.clone();
+    let mut parts = args.splitn(2, '/');
+    let kitsu_id: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let offset: i64 = parts
+      .next()
+      .unwrap_or("0")
+      .trim_matches('/')
+      .parse()
+      .unwrap_or(0);
+
+    let bot1 = ctx.bot.clone();
+    let bot2 = ctx.bot.clone();
     Box::new(
-      self
+      ctx
         .api
         .fetch_anime(kitsu_id, offset)
         .and_then(move |(prev, next, entries, animes)| {
           Ok(parse_anime_list(kitsu_id, prev, next, entries, animes))
         })
         .and_then(move |(text, buttons)| {
-          bot1.edit_inline_keyboard(msg_id, chat_id, text, Some(ParseMode::HTML), Some(buttons))
+          bot1.edit_inline_keyboard(
+            msg_id,
+            chat_id,
+            text,
+            Some(ParseMode::HTML),
+            Some(buttons),
+            None,
+          )
         })
         .and_then(move |_| bot2.answer_query(query_id, None, None))
         .and_then(|_| Ok(())),
     )
   }
+}
+
+struct DetailCommand;
+
+impl QueryCommandHandler for DetailCommand {
+  fn prefix(&self) -> &str {
+    "detail"
+  }
 
-  fn detail(
-    &self,
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
     msg_id: i64,
     chat_id: i64,
-    kitsu_id: i64,
-    anime_id: i64,
-    query_id: String,
+    _user_id: i64,
+    _query_id: String,
+    args: &str,
   ) -> Box<Future<Item = (), Error = Error>> {
-    let bot = self.bot.clone();
+    let mut parts = args.splitn(2, '/');
+    let kitsu_id: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let anime_id: i64 = parts
+      .next()
+      .unwrap_or("0")
+      .trim_matches('/')
+      .parse()
+      .unwrap_or(0);
+
+    let bot = ctx.bot.clone();
     Box::new(
-      self
+      ctx
         .api
         .get_anime(kitsu_id, anime_id)
         .and_then(move |pair| Ok(parse_anime_detail(kitsu_id, pair)))
         .and_then(move |(text, buttons)| {
-          bot.edit_inline_keyboard(msg_id, chat_id, text, Some(ParseMode::HTML), Some(buttons))
+          // Suppress the link preview: the card already renders the poster inline.
+          bot.edit_inline_keyboard(
+            msg_id,
+            chat_id,
+            text,
+            Some(ParseMode::HTML),
+            Some(buttons),
+            Some(true),
+          )
         })
         .and_then(|msg| {
           info!("send message: {:?} in {:?}", msg.text, msg.chat);
@@ -223,20 +540,33 @@ impl Handler {
         }),
     )
   }
+}
 
-  fn progress(
+struct ProgressCommand;
+
+impl QueryCommandHandler for ProgressCommand {
+  fn prefix(&self) -> &str {
+    "progress"
+  }
+
+  fn execute(
     &mut self,
+    ctx: &mut Context,
     msg_id: i64,
     chat_id: i64,
     user_id: i64,
-    kitsu_id: i64,
-    anime_id: String,
-    progress: i64,
-    entry_id: String,
     query_id: String,
+    args: &str,
   ) -> Box<Future<Item = (), Error = Error>> {
-    let bot = self.bot.clone();
-    let token = self.db.get_token(user_id, kitsu_id);
+    // args: "{kitsu_id}/{anime_id}/{progress}/{entry_id}/"
+    let mut parts = args.trim_matches('/').splitn(4, '/');
+    let kitsu_id: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let anime_id = parts.next().unwrap_or("").to_owned();
+    let progress: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let entry_id = parts.next().unwrap_or("").to_owned();
+
+    let bot = ctx.bot.clone();
+    let token = ctx.db.get_token(user_id, kitsu_id);
     let text = format!("Successful update to episode {}", progress);
     let buttons = vec![
       vec![
@@ -263,11 +593,136 @@ impl Handler {
           .and_then(|_| Ok(())),
       ),
       Some(token) => Box::new(
-        self
+        ctx
           .api
           .update_anime_entry(token, entry_id, progress, anime_id)
           .and_then(move |_| {
-            bot.edit_inline_keyboard(msg_id, chat_id, text, Some(ParseMode::HTML), Some(buttons))
+            bot.edit_inline_keyboard(
+              msg_id,
+              chat_id,
+              text,
+              Some(ParseMode::HTML),
+              Some(buttons),
+              None,
+            )
+          })
+          .and_then(|msg| {
+            info!("send message: {:?} in {:?}", msg.text, msg.chat);
+            Ok(())
+          }),
+      ),
+    }
+  }
+}
+
+struct SearchOffsetCommand;
+
+impl QueryCommandHandler for SearchOffsetCommand {
+  fn prefix(&self) -> &str {
+    "search"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    msg_id: i64,
+    chat_id: i64,
+    _user_id: i64,
+    query_id: String,
+    args: &str,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    // args: "{kitsu_id}/{offset}/{query}/"
+    let mut parts = args.trim_matches('/').splitn(3, '/');
+    let kitsu_id: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let offset: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let query = parts.next().unwrap_or("").to_owned();
+
+    let bot1 = ctx.bot.clone();
+    let bot2 = ctx.bot.clone();
+    Box::new(
+      ctx
+        .api
+        .search_anime(query.clone(), offset)
+        .and_then(move |(prev, next, animes)| {
+          Ok(parse_search_results(kitsu_id, query, prev, next, animes))
+        })
+        .and_then(move |(text, buttons)| {
+          bot1.edit_inline_keyboard(
+            msg_id,
+            chat_id,
+            text,
+            Some(ParseMode::HTML),
+            Some(buttons),
+            None,
+          )
+        })
+        .and_then(move |_| bot2.answer_query(query_id, None, None))
+        .and_then(|_| Ok(())),
+    )
+  }
+}
+
+struct AddCommand;
+
+impl QueryCommandHandler for AddCommand {
+  fn prefix(&self) -> &str {
+    "add"
+  }
+
+  fn execute(
+    &mut self,
+    ctx: &mut Context,
+    msg_id: i64,
+    chat_id: i64,
+    user_id: i64,
+    query_id: String,
+    args: &str,
+  ) -> Box<Future<Item = (), Error = Error>> {
+    // args: "{kitsu_id}/{anime_id}/"
+    let mut parts = args.trim_matches('/').splitn(2, '/');
+    let kitsu_id: i64 = parts.next().unwrap_or("0").parse().unwrap_or(0);
+    let anime_id = parts.next().unwrap_or("").to_owned();
+
+    let bot = ctx.bot.clone();
+    let token = ctx.db.get_token(user_id, kitsu_id);
+    let text = String::from("Added to your list!");
+    let buttons = vec![
+      vec![
+        InlineKeyboardButton::with_callback_data(
+          "back to anime".to_owned(),
+          format!("/{}/detail/{}/", kitsu_id, anime_id),
+        ),
+      ],
+      vec![
+        InlineKeyboardButton::with_callback_data(
+          "back to list".to_owned(),
+          format!("/{}/offset/0/", kitsu_id),
+        ),
+      ],
+    ];
+    match token {
+      None => Box::new(
+        bot
+          .answer_query(
+            query_id,
+            Some(String::from("Non-registered user")),
+            Some(true),
+          )
+          .and_then(|_| Ok(())),
+      ),
+      Some(token) => Box::new(
+        ctx
+          .api
+          .create_anime_entry(token, anime_id)
+          .and_then(move |_| {
+            bot.edit_inline_keyboard(
+              msg_id,
+              chat_id,
+              text,
+              Some(ParseMode::HTML),
+              Some(buttons),
+              None,
+            )
           })
           .and_then(|msg| {
             info!("send message: {:?} in {:?}", msg.text, msg.chat);
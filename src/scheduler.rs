@@ -0,0 +1,109 @@
+use std::time::{Duration, Instant};
+
+use futures::{stream, Future, IntoFuture, Stream};
+use tokio::timer::Interval;
+
+use bot::telegram::Bot;
+use database::Database;
+use kitsu::{Anime, Api, LibraryEntry};
+use types::telegram::{InlineKeyboardButton, ParseMode};
+
+/// Default cadence for the new-episode sweep; callers may pass their own
+/// interval (e.g. from config) to `spawn`.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Walks every notify-opted-in user on a fixed `interval`, diffing their
+/// Kitsu library against the last episode count we've seen and pinging
+/// them about anything new. Never resolves; intended to be handed to
+/// `tokio::spawn`.
+pub fn spawn(
+  bot: Bot,
+  api: Api,
+  db: Database,
+  interval: Duration,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+  Box::new(
+    Interval::new(Instant::now() + interval, interval)
+      .map_err(|err| error!("scheduler: timer failed: {}", err))
+      .for_each(move |_| poll_once(bot.clone(), api.clone(), db.clone())),
+  )
+}
+
+fn poll_once(bot: Bot, api: Api, db: Database) -> Box<Future<Item = (), Error = ()> + Send> {
+  Box::new(
+    stream::iter_ok(db.notify_subscribers()).for_each(move |(user_id, kitsu_id)| {
+      let bot = bot.clone();
+      let db = db.clone();
+
+      api
+        .fetch_anime(kitsu_id, 0)
+        .map_err(move |err| error!("scheduler: failed to fetch anime for {}: {}", kitsu_id, err))
+        .and_then(move |(_prev, _next, entries, animes)| {
+          stream::iter_ok(entries.into_iter().zip(animes.into_iter())).for_each(
+            move |(entry, anime)| {
+              notify_if_new_episode(bot.clone(), db.clone(), user_id, kitsu_id, entry, anime)
+            },
+          )
+        })
+        // One subscriber's fetch/send failure is already logged above; don't
+        // let it cancel the sweep for every other subscriber.
+        .then(|_| Ok(()))
+    }),
+  )
+}
+
+fn notify_if_new_episode(
+  bot: Bot,
+  db: Database,
+  user_id: i64,
+  kitsu_id: i64,
+  entry: LibraryEntry,
+  anime: Anime,
+) -> Box<Future<Item = (), Error = ()> + Send> {
+  let latest = match anime.episode_count {
+    Some(count) => count,
+    None => return Box::new(Ok(()).into_future()),
+  };
+
+  match db.get_last_episode(user_id, anime.id) {
+    // First sweep for this (user, anime) pair: seed the baseline with the
+    // current episode count (or their progress, if that is higher) instead
+    // of treating it as 0, or every show already in the library would fire
+    // a notification once /notify is turned on.
+    None => {
+      db.set_last_episode(user_id, anime.id, latest.max(entry.progress));
+      return Box::new(Ok(()).into_future());
+    }
+    Some(seen) if latest <= seen => return Box::new(Ok(()).into_future()),
+    Some(_) => {}
+  }
+
+  let buttons = vec![vec![InlineKeyboardButton::with_callback_data(
+    "mark watched".to_owned(),
+    format!("/{}/progress/{}/{}/{}/", kitsu_id, anime.id, latest, entry.id),
+  )]];
+
+  Box::new(
+    bot
+      .send_message(
+        user_id,
+        format!("<b>{}</b> just aired episode {}.", anime.title, latest),
+        Some(ParseMode::HTML),
+        Some(buttons),
+        None,
+      )
+      // Swallow a failed send instead of aborting the rest of this user's
+      // library: log it and leave the baseline untouched so it's retried
+      // on the next sweep.
+      .then(move |result| {
+        match result {
+          Ok(msg) => {
+            info!("send message: {:?} in {:?}", msg.text, msg.chat);
+            db.set_last_episode(user_id, anime.id, latest);
+          }
+          Err(err) => error!("scheduler: failed to notify user: {}", err),
+        }
+        Ok(())
+      }),
+  )
+}